@@ -2,7 +2,7 @@
 
 //! A 3dsMax / Blender style camera that orbits about a target position
 
-use vecmath::{ Vector3, vec3_add, vec3_scale };
+use vecmath::{ Vector3, vec3_add, vec3_sub, vec3_scale, vec3_dot, vec3_neg };
 use vecmath::traits::Float;
 
 use quaternion;
@@ -13,17 +13,36 @@ use input::Button::{ Keyboard, Mouse };
 
 use Camera;
 
+/// Small margin kept between `pitch_min`/`pitch_max` and vertical, avoiding the
+/// degenerate look-straight-up/down orientation where yaw becomes undefined.
+const PITCH_EPSILON: f64 = 0.001;
+
 bitflags!(
-    pub struct Mode: u8 {
-        const ORBIT_BUTTON = 0b00000001;
-        const ZOOM_BUTTON  = 0b00000010;
-        const PAN_BUTTON   = 0b00000100;
-        const ORBIT_MOD    = 0b00001000;
-        const ZOOM_MOD     = 0b00010000;
-        const PAN_MOD      = 0b00100000;
+    pub struct Mode: u16 {
+        const ORBIT_BUTTON  = 0b0000000001;
+        const ZOOM_BUTTON   = 0b0000000010;
+        const PAN_BUTTON    = 0b0000000100;
+        const ORBIT_MOD     = 0b0000001000;
+        const ZOOM_MOD      = 0b0000010000;
+        const PAN_MOD       = 0b0000100000;
+        const PAN_FORWARD   = 0b0001000000;
+        const PAN_BACKWARD  = 0b0010000000;
+        const PAN_LEFT      = 0b0100000000;
+        const PAN_RIGHT     = 0b1000000000;
     }
 );
 
+/// How scroll/drag input is converted into a change in `distance`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ZoomMode {
+    /// `distance += dy * zoom_speed * distance` (the original behavior).
+    /// Equal scroll amounts in and out do not cancel out exactly.
+    Linear,
+    /// `distance *= exp(-dy * zoom_speed)`. Each unit of scroll is a constant
+    /// multiplicative step, giving uniform perceptual zoom and exact reversibility.
+    Exponential,
+}
+
 /// Specifies key bindings and speed modifiers for OrbitZoomCamera
 pub struct OrbitZoomCameraSettings<T=f32> {
 
@@ -60,6 +79,42 @@ pub struct OrbitZoomCameraSettings<T=f32> {
 
     /// Modifier for zoom speed (arbitrary unit)
     pub zoom_speed: T,
+
+    /// Time constant (in seconds) of the exponential smoothing applied to the camera
+    /// as it chases the input-driven goal state in `camera`. `0` disables smoothing,
+    /// snapping straight to the goal state as before.
+    pub smoothing_time: T,
+
+    /// Lower bound for `pitch`, preventing the orbit from flipping over the bottom pole
+    pub pitch_min: T,
+
+    /// Upper bound for `pitch`, preventing the orbit from flipping over the top pole
+    pub pitch_max: T,
+
+    /// How `zoom_speed` maps scroll/drag input to a change in `distance`
+    pub zoom_mode: ZoomMode,
+
+    /// Which key to hold to pan forward with `update_edge_pan` (if any)
+    pub pan_forward_key: Option<Key>,
+
+    /// Which key to hold to pan backward with `update_edge_pan` (if any)
+    pub pan_backward_key: Option<Key>,
+
+    /// Which key to hold to pan left with `update_edge_pan` (if any)
+    pub pan_left_key: Option<Key>,
+
+    /// Which key to hold to pan right with `update_edge_pan` (if any)
+    pub pan_right_key: Option<Key>,
+
+    /// Distance, in window coordinates (e.g. pixels), from a window border within which
+    /// the cursor triggers edge panning in `update_edge_pan`. `0` disables edge panning.
+    pub edge_pan_margin: T,
+
+    /// When orbiting past vertical (e.g. with widened `pitch_min`/`pitch_max`) is allowed,
+    /// negate the horizontal drag contribution to `yaw` while upside-down, so dragging
+    /// left always visually rotates the scene left. Off by default, preserving the
+    /// original clamped behavior where yaw direction never needs to flip.
+    pub invert_yaw_when_upside_down: bool,
 }
 
 impl<T: Float> OrbitZoomCameraSettings<T> {
@@ -79,6 +134,16 @@ impl<T: Float> OrbitZoomCameraSettings<T> {
             pitch_speed: T::from_f32(0.1),
             pan_speed: T::from_f32(0.1),
             zoom_speed: T::from_f32(0.1),
+            smoothing_time: T::zero(),
+            pitch_min: T::from_f64(-(::std::f64::consts::FRAC_PI_2) + PITCH_EPSILON),
+            pitch_max: T::from_f64(::std::f64::consts::FRAC_PI_2 - PITCH_EPSILON),
+            zoom_mode: ZoomMode::Linear,
+            pan_forward_key: None,
+            pan_backward_key: None,
+            pan_left_key: None,
+            pan_right_key: None,
+            edge_pan_margin: T::zero(),
+            invert_yaw_when_upside_down: false,
         }
     }
 
@@ -145,6 +210,65 @@ impl<T: Float> OrbitZoomCameraSettings<T> {
             .. self
         }
     }
+
+    /// Set the smoothing time constant (tau), in seconds. `0` snaps instantly to the
+    /// goal state instead of easing toward it.
+    pub fn smoothing_time(self, tau: T) -> OrbitZoomCameraSettings<T> {
+        OrbitZoomCameraSettings {
+            smoothing_time: tau,
+            .. self
+        }
+    }
+
+    /// Set the lower and upper bounds that `pitch` is clamped to
+    pub fn pitch_limits(self, min: T, max: T) -> OrbitZoomCameraSettings<T> {
+        OrbitZoomCameraSettings {
+            pitch_min: min,
+            pitch_max: max,
+            .. self
+        }
+    }
+
+    /// Set how scroll/drag input maps to a change in `distance`
+    pub fn zoom_mode(self, mode: ZoomMode) -> OrbitZoomCameraSettings<T> {
+        OrbitZoomCameraSettings {
+            zoom_mode: mode,
+            .. self
+        }
+    }
+
+    /// Set the keys used to pan with `update_edge_pan`
+    pub fn pan_keys(
+        self,
+        forward: Option<Key>,
+        backward: Option<Key>,
+        left: Option<Key>,
+        right: Option<Key>
+    ) -> OrbitZoomCameraSettings<T> {
+        OrbitZoomCameraSettings {
+            pan_forward_key: forward,
+            pan_backward_key: backward,
+            pan_left_key: left,
+            pan_right_key: right,
+            .. self
+        }
+    }
+
+    /// Set the edge-pan margin, in window coordinates. `0` disables edge panning.
+    pub fn edge_pan_margin(self, margin: T) -> OrbitZoomCameraSettings<T> {
+        OrbitZoomCameraSettings {
+            edge_pan_margin: margin,
+            .. self
+        }
+    }
+
+    /// Set whether `yaw` direction flips while the camera is upside-down
+    pub fn invert_yaw_when_upside_down(self, invert: bool) -> OrbitZoomCameraSettings<T> {
+        OrbitZoomCameraSettings {
+            invert_yaw_when_upside_down: invert,
+            .. self
+        }
+    }
 }
 
 /// A 3dsMax / Blender-style camera that orbits around a target point
@@ -176,8 +300,28 @@ pub struct OrbitZoomCamera<T=f32> {
     /// Settings for the camera
     pub settings: OrbitZoomCameraSettings<T>,
 
+    /// World-space point most recently passed to `control_camera_about`, which orbits and
+    /// zooms around it instead of `target`; typically a raycast hit under the cursor.
+    /// `control_camera` does not read this field.
+    pub pivot: Option<Vector3<T>>,
+
     /// Current camera control mode activated
     mode: Mode,
+
+    /// Smoothed target actually rendered by `camera`, chasing `target` at the rate given
+    /// by `settings.smoothing_time`.
+    current_target: Vector3<T>,
+
+    /// Smoothed distance actually rendered by `camera`, chasing `distance`.
+    current_distance: T,
+
+    /// Smoothed rotation actually rendered by `camera`, chasing `rotation`.
+    current_rotation: Quaternion<T>,
+
+    /// Whether the camera's local up currently points against world up, i.e. orbit has
+    /// carried it past vertical. Recomputed in `control_camera` whenever pitch crosses
+    /// ±90°, and used to un-invert yaw when `invert_yaw_when_upside_down` is set.
+    upside_down: bool,
 }
 
 
@@ -206,19 +350,42 @@ OrbitZoomCamera<T> {
             distance_far_limit: T::from_f32(1000.0),
             pitch: T::zero(),
             yaw: T::zero(),
+            pivot: None,
             mode,
+            current_target: target,
+            current_distance: T::from_f32(10.0),
+            current_rotation: quaternion::id(),
+            upside_down: false,
             settings,
         }
     }
 
-    /// Return a Camera for the current OrbitZoomCamera configuration
-    pub fn camera(&self, _dt: f64) -> Camera<T> {
+    /// Return a Camera for the current OrbitZoomCamera configuration, advancing the
+    /// rendered (current) state toward the input-driven goal state (`target`, `rotation`,
+    /// `distance`) by `dt` seconds using exponential smoothing (see `smoothing_time`).
+    pub fn camera(&mut self, dt: f64) -> Camera<T> {
+        let tau = self.settings.smoothing_time;
+        if tau <= T::zero() {
+            self.current_target = self.target;
+            self.current_distance = self.distance;
+            self.current_rotation = self.rotation;
+        } else {
+            let alpha = T::one() - (-T::from_f64(dt) / tau).exp();
+            self.current_target = vec3_add(
+                self.current_target,
+                vec3_scale(vec3_sub(self.target, self.current_target), alpha)
+            );
+            self.current_distance = self.current_distance +
+                (self.distance - self.current_distance) * alpha;
+            self.current_rotation = nlerp(self.current_rotation, self.rotation, alpha);
+        }
+
         let target_to_camera = quaternion::rotate_vector(
-            self.rotation,
-            [T::zero(), T::zero(), self.distance]
+            self.current_rotation,
+            [T::zero(), T::zero(), self.current_distance]
         );
-        let mut camera = Camera::new(vec3_add(self.target, target_to_camera));
-        camera.set_rotation(self.rotation);
+        let mut camera = Camera::new(vec3_add(self.current_target, target_to_camera));
+        camera.set_rotation(self.current_rotation);
         camera
     }
 
@@ -235,6 +402,11 @@ OrbitZoomCamera<T> {
     /// camera rotation
     pub fn init(&mut self) {
         self.rotation = Self::rotation_from_yaw_and_pitch(self.yaw, self.pitch);
+        // Snap the rendered state to the goal state so smoothing starts from here,
+        // rather than easing in from the struct's defaults.
+        self.current_target = self.target;
+        self.current_distance = self.distance;
+        self.current_rotation = self.rotation;
     }
 
     fn is_orbit(&self) -> bool {
@@ -272,7 +444,12 @@ OrbitZoomCamera<T> {
         } else if self.is_zoom() {
 
             // Zoom to / from target
-            let new_dist = self.distance + dy * self.settings.zoom_speed*self.distance;
+            let new_dist = match self.settings.zoom_mode {
+                ZoomMode::Linear =>
+                    self.distance + dy * self.settings.zoom_speed*self.distance,
+                ZoomMode::Exponential =>
+                    self.distance * (-dy * self.settings.zoom_speed).exp(),
+            };
             self.distance =
                 if new_dist > self.distance_far_limit {
                     self.distance_far_limit
@@ -288,12 +465,99 @@ OrbitZoomCamera<T> {
             let dx = dx * self.settings.orbit_speed;
             let dy = dy * self.settings.orbit_speed;
 
+            let dx = if self.settings.invert_yaw_when_upside_down && self.upside_down {
+                -dx
+            } else {
+                dx
+            };
+
             self.yaw = self.yaw + dx;
             self.pitch = self.pitch + dy*self.settings.pitch_speed;
+            self.pitch =
+                if self.pitch > self.settings.pitch_max {
+                    self.settings.pitch_max
+                } else if self.pitch < self.settings.pitch_min {
+                    self.settings.pitch_min
+                } else {
+                    self.pitch
+                };
             self.rotation = Self::rotation_from_yaw_and_pitch(self.yaw, self.pitch);
+
+            let up = quaternion::rotate_vector(self.rotation, [T::zero(), T::one(), T::zero()]);
+            self.upside_down = up[1] < T::zero();
+        }
+    }
+
+    /// Like `control_camera`, but orbits and zooms about `pivot` (a world-space point,
+    /// typically a raycast hit under the cursor) instead of the fixed target-to-camera axis.
+    /// The pivot stays fixed on screen: zooming moves `target` toward `pivot` by the same
+    /// fraction the distance changes, and orbiting rotates `target` about `pivot`.
+    pub fn control_camera_about(&mut self, dx: T, dy: T, pivot: Vector3<T>) {
+        self.pivot = Some(pivot);
+
+        if self.is_zoom() {
+
+            let old_distance = self.distance;
+            self.control_camera(dx, dy);
+            let f = self.distance / old_distance;
+            self.target = vec3_add(pivot, vec3_scale(vec3_sub(self.target, pivot), f));
+
+        } else if self.is_orbit() {
+
+            let old_rotation = self.rotation;
+            self.control_camera(dx, dy);
+            let delta_rotation = quaternion::mul(self.rotation, quaternion::conj(old_rotation));
+            let local = vec3_sub(self.target, pivot);
+            self.target = vec3_add(pivot, quaternion::rotate_vector(delta_rotation, local));
+
+        } else {
+            self.control_camera(dx, dy);
         }
     }
 
+    /// Drive RTS-style continuous panning: pans `target` while `cursor` sits within
+    /// `settings.edge_pan_margin` of a `window_size` border, and/or while any of the
+    /// configured pan keys are held. Call this once per frame with the cursor position
+    /// and window size in the same window coordinates, and the frame's `dt`.
+    pub fn update_edge_pan(&mut self, cursor: [f64; 2], window_size: [f64; 2], dt: f64) {
+        let _0 = T::zero();
+        let _1 = T::one();
+        let margin = self.settings.edge_pan_margin;
+
+        // How far past the margin `pos` has pushed into the near (negative) or
+        // far (positive) border of `[0, size]`, as a fraction of the margin.
+        let edge = |pos: f64, size: f64| -> T {
+            if margin <= _0 {
+                return _0;
+            }
+            let pos = T::from_f64(pos);
+            let size = T::from_f64(size);
+            if pos < margin {
+                -(margin - pos) / margin
+            } else if pos > size - margin {
+                (pos - (size - margin)) / margin
+            } else {
+                _0
+            }
+        };
+
+        let mut dx = edge(cursor[0], window_size[0]);
+        let mut dy = -edge(cursor[1], window_size[1]);
+
+        if self.mode.contains(PAN_RIGHT) { dx = dx + _1; }
+        if self.mode.contains(PAN_LEFT) { dx = dx - _1; }
+        if self.mode.contains(PAN_FORWARD) { dy = dy + _1; }
+        if self.mode.contains(PAN_BACKWARD) { dy = dy - _1; }
+
+        let speed = self.settings.pan_speed * self.distance * T::from_f64(dt);
+        let right = quaternion::rotate_vector(self.rotation, [_1, _0, _0]);
+        let up = quaternion::rotate_vector(self.rotation, [_0, _1, _0]);
+        self.target = vec3_add(
+            vec3_add(self.target, vec3_scale(up, dy * speed)),
+            vec3_scale(right, dx * speed)
+        );
+    }
+
     fn mod_key_pressed(&self) -> bool {
         let mut is_pressed = false;
         if let Some(_) = self.settings.orbit_mod {
@@ -348,6 +612,10 @@ OrbitZoomCamera<T> {
             if Some(x) == self.settings.orbit_mod.map(|a| Keyboard(a)) { self.mode.insert(ORBIT_MOD); }
             if Some(x) == self.settings.pan_mod.map(|a| Keyboard(a)) { self.mode.insert(PAN_MOD); }
             if Some(x) == self.settings.zoom_mod.map(|a| Keyboard(a)) { self.mode.insert(ZOOM_MOD); }
+            if Some(x) == self.settings.pan_forward_key.map(|a| Keyboard(a)) { self.mode.insert(PAN_FORWARD); }
+            if Some(x) == self.settings.pan_backward_key.map(|a| Keyboard(a)) { self.mode.insert(PAN_BACKWARD); }
+            if Some(x) == self.settings.pan_left_key.map(|a| Keyboard(a)) { self.mode.insert(PAN_LEFT); }
+            if Some(x) == self.settings.pan_right_key.map(|a| Keyboard(a)) { self.mode.insert(PAN_RIGHT); }
         });
 
         e.release(|x| {
@@ -357,7 +625,29 @@ OrbitZoomCamera<T> {
             if Some(x) == self.settings.orbit_mod.map(|a| Keyboard(a)) { self.mode.remove(ORBIT_MOD); }
             if Some(x) == self.settings.pan_mod.map(|a| Keyboard(a)) { self.mode.remove(PAN_MOD); }
             if Some(x) == self.settings.zoom_mod.map(|a| Keyboard(a)) { self.mode.remove(ZOOM_MOD); }
+            if Some(x) == self.settings.pan_forward_key.map(|a| Keyboard(a)) { self.mode.remove(PAN_FORWARD); }
+            if Some(x) == self.settings.pan_backward_key.map(|a| Keyboard(a)) { self.mode.remove(PAN_BACKWARD); }
+            if Some(x) == self.settings.pan_left_key.map(|a| Keyboard(a)) { self.mode.remove(PAN_LEFT); }
+            if Some(x) == self.settings.pan_right_key.map(|a| Keyboard(a)) { self.mode.remove(PAN_RIGHT); }
         });
     }
 
 }
+
+/// Normalized linear interpolation between two quaternions, taking the shortest path.
+fn nlerp<T: Float>(a: Quaternion<T>, b: Quaternion<T>, t: T) -> Quaternion<T> {
+    let (aw, av) = a;
+    let (bw, bv) = b;
+
+    // Negate b if it's on the opposite hemisphere, so we interpolate the short way around.
+    let (bw, bv) = if aw*bw + vec3_dot(av, bv) < T::zero() {
+        (-bw, vec3_neg(bv))
+    } else {
+        (bw, bv)
+    };
+
+    let w = aw + (bw - aw) * t;
+    let v = vec3_add(av, vec3_scale(vec3_sub(bv, av), t));
+    let len = (w*w + vec3_dot(v, v)).sqrt();
+    (w / len, vec3_scale(v, T::one() / len))
+}